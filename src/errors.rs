@@ -8,5 +8,17 @@ error_chain! {
             description("invalid program")
             display("invalid program '{}'", m)
         }
+        RecursionLimit(limit: usize) {
+            description("recursion limit exceeded")
+            display("recursion limit of {} exceeded", limit)
+        }
+        ImportCycle(path: String) {
+            description("import cycle")
+            display("import cycle detected while loading '{}'", path)
+        }
+        Parse(rendered: String, line: usize, col: usize) {
+            description("parse error")
+            display("{}", rendered)
+        }
     }
 }
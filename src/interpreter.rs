@@ -1,9 +1,13 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, Read, Write};
 use std::fs::File;
 use std::iter::Iterator;
 use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use logos::Logos;
 
 use super::errors;
 
@@ -17,6 +21,15 @@ pub struct Assignment {
 pub enum Statement {
     Assignment(Assignment),
     Execution(Vec<String>),
+    If {
+        cond: Vec<String>,
+        then_body: Vec<Statement>,
+        else_body: Option<Vec<Statement>>,
+    },
+    While {
+        cond: Vec<String>,
+        body: Vec<Statement>,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -24,47 +37,234 @@ pub struct Function(Vec<Statement>);
 
 pub type Program = BTreeMap<String, Function>;
 
+/// Strip the surrounding `quote` characters from a string literal slice and
+/// resolve backslash escapes, preserving all internal whitespace.
+fn unquote(slice: &str, quote: char) -> String {
+    let inner = &slice[quote.len_utf8()..slice.len() - quote.len_utf8()];
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('r') => out.push('\r'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Lex a `$(...)` command substitution as a single token. The opening `$(` has
+/// already been matched; this scans the remainder for the balanced closing `)`
+/// so that whitespace inside the substitution is preserved as one argument.
+fn lex_substitution(lex: &mut logos::Lexer<Token>) -> Option<String> {
+    let rest = lex.remainder();
+    let mut depth = 1;
+    let mut end = None;
+    for (off, c) in rest.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    end = Some(off);
+                    break;
+                }
+            },
+            _ => {},
+        }
+    }
+    let off = end?;
+    lex.bump(off + 1);
+    Some(format!("$({})", &rest[..off]))
+}
+
+/// The tokens produced by the lexer. Whitespace between tokens is discarded by
+/// `logos`; the parser recovers word boundaries from token spans instead.
+#[derive(Logos, Clone, Debug, PartialEq)]
+enum Token {
+    #[token("(){")]
+    LBraceFuncStart,
+
+    #[token("{")]
+    LBrace,
+
+    #[token("}")]
+    RBrace,
+
+    #[token("(")]
+    LParen,
+
+    #[token(")")]
+    RParen,
+
+    #[token("=")]
+    Assign,
+
+    // `$(` is recognised before a bare `$` so a command substitution stays a
+    // single token; `$name` still lexes as `Dollar` followed by a `Word`.
+    #[token("$(", lex_substitution)]
+    Subst(String),
+
+    #[token("$")]
+    Dollar,
+
+    #[token("\n")]
+    Newline,
+
+    #[regex(r#""([^"\\]|\\.)*""#, |lex| unquote(lex.slice(), '"'))]
+    #[regex(r#"'([^'\\]|\\.)*'"#, |lex| unquote(lex.slice(), '\''))]
+    StringLit(String),
+
+    #[regex(r"#[^\n]*", logos::skip)]
+    Comment,
+
+    #[regex(r"[^\s=$(){}#'\x22]+", |lex| lex.slice().to_owned())]
+    Word(String),
+
+    #[error]
+    #[regex(r"[ \t\r]+", logos::skip)]
+    Error,
+}
+
+impl Token {
+    /// The literal text a token contributes when it is glued to its neighbours
+    /// to reconstruct a single argument.
+    fn text(&self) -> &str {
+        match *self {
+            Token::LBraceFuncStart => "(){",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::Assign => "=",
+            Token::Dollar => "$",
+            Token::Newline => "\n",
+            Token::Subst(ref s) | Token::StringLit(ref s) | Token::Word(ref s) => s,
+            Token::Comment | Token::Error => "",
+        }
+    }
+}
+
+type Spanned = (Token, Range<usize>);
+
 #[derive(Clone, Debug)]
 enum LexicalPattern {
     FuncStart(String),
+    IfStart(Vec<String>),
+    WhileStart(Vec<String>),
+    ElseStart,
     FuncEnd,
     Statement(Statement),
     Empty,
 }
 
 impl LexicalPattern {
-    fn from_line(s: &str) -> Option<LexicalPattern> {
-        let trimmed = s.trim();
-
-        if trimmed.is_empty() {
+    /// Classify a single logical line (the tokens between two `Newline`s) into
+    /// a pattern. Adjacent tokens — those with no intervening whitespace — are
+    /// glued into one argument, so `echo "hello world"` yields a single word.
+    fn from_tokens(line: &[Spanned]) -> Option<LexicalPattern> {
+        if line.is_empty() {
             return Some(LexicalPattern::Empty);
         }
 
-        if trimmed == "}" {
+        if line.len() == 1 && line[0].0 == Token::RBrace {
             return Some(LexicalPattern::FuncEnd);
         }
 
-        if trimmed.ends_with("(){") {
-            let func_name = &trimmed[..trimmed.len() - 3];
-            if !name_valid(func_name) {
+        // `else {` — the transition from an `if` body to its `else` body.
+        if line.len() == 2 && line[0].0 == Token::Word("else".to_owned()) && line[1].0 == Token::LBrace {
+            return Some(LexicalPattern::ElseStart);
+        }
+
+        // A block header ends in `(){`. The words before it are either a single
+        // function name, or an `if`/`while` keyword followed by the condition
+        // command.
+        if let Some((Token::LBraceFuncStart, _)) = line.last() {
+            let head = split_words(&line[..line.len() - 1]);
+            match head.split_first() {
+                Some((kw, rest)) if kw == "if" => return Some(LexicalPattern::IfStart(rest.to_vec())),
+                Some((kw, rest)) if kw == "while" => return Some(LexicalPattern::WhileStart(rest.to_vec())),
+                _ => {}
+            }
+
+            let name = glue(&line[..line.len() - 1]);
+            if !name_valid(&name) {
                 return None;
-            } else {
-                return Some(LexicalPattern::FuncStart(func_name.to_owned()));
             }
+            return Some(LexicalPattern::FuncStart(name));
         }
 
-        if let Some(idx) = trimmed.find('=') {
-            let var_name = &trimmed[..idx];
+        // `name=value` — an assignment is a single word immediately followed by
+        // an `=`. Anything else (a space before the `=`, or a leading command
+        // word) is an ordinary execution whose argument merely contains `=`.
+        if let Some(idx) = first_top_level_assign(line) {
+            let variable = glue(&line[..idx]);
+            if name_valid(&variable) {
+                let value = join(&line[idx + 1..]);
+                return Some(LexicalPattern::Statement(Statement::Assignment(Assignment { variable: variable, value: value })));
+            }
+        }
 
-            if !name_valid(var_name) {
-                return None;
-            } else {
-                return Some(LexicalPattern::Statement(Statement::Assignment(Assignment { variable: var_name.to_owned(), value: (&trimmed[idx + 1..]).to_owned() })));
+        Some(LexicalPattern::Statement(Statement::Execution(split_words(line))))
+    }
+}
+
+/// Concatenate the text of every token in a run, ignoring whitespace gaps.
+fn glue(tokens: &[Spanned]) -> String {
+    tokens.iter().map(|&(ref t, _)| t.text()).collect()
+}
+
+/// Join the words of a run with single spaces, used to reconstruct an
+/// assignment value that may span several whitespace-separated pieces.
+fn join(tokens: &[Spanned]) -> String {
+    split_words(tokens).join(" ")
+}
+
+/// Split a run of tokens into arguments, gluing tokens with no whitespace
+/// between their spans into the same argument.
+fn split_words(tokens: &[Spanned]) -> Vec<String> {
+    let mut words = vec![];
+    let mut cur = String::new();
+    let mut prev_end: Option<usize> = None;
+
+    for &(ref t, ref span) in tokens {
+        if let Some(end) = prev_end {
+            if span.start > end {
+                words.push(mem::replace(&mut cur, String::new()));
             }
         }
+        cur.push_str(t.text());
+        prev_end = Some(span.end);
+    }
+    if prev_end.is_some() {
+        words.push(cur);
+    }
+    words
+}
 
-        Some(LexicalPattern::Statement(Statement::Execution(trimmed.split_whitespace().map(String::from).collect())))
+/// Index of the first `=` that separates an assignment, i.e. one glued to a
+/// single leading word. Returns `None` when the `=` is part of an argument.
+fn first_top_level_assign(line: &[Spanned]) -> Option<usize> {
+    for (i, &(ref t, ref span)) in line.iter().enumerate() {
+        if *t != Token::Assign {
+            continue;
+        }
+        // The tokens before the `=` must form one unbroken word (no gaps) and
+        // the `=` itself must be glued to it.
+        let glued = line[..i].iter().zip(line[1..i + 1].iter())
+            .all(|(&(_, ref a), &(_, ref b))| b.start == a.end);
+        if i > 0 && glued && span.start == line[i - 1].1.end {
+            return Some(i);
+        }
+        return None;
     }
+    None
 }
 
 pub fn name_valid(name: &str) -> bool {
@@ -77,93 +277,237 @@ pub fn name_valid(name: &str) -> bool {
     name.find(bad).is_none()
 }
 
+/// A block whose body is still being collected. Blocks nest, so the parser
+/// keeps a stack of these: a function at the bottom and any `if`/`while` blocks
+/// opened inside it stacked on top.
 #[derive(Clone, Debug)]
-enum ParseState {
-    ConstructFunc(String, Function),
-    Outside,
+enum Builder {
+    Func(String, Vec<Statement>),
+    If {
+        cond: Vec<String>,
+        then_body: Vec<Statement>,
+        else_body: Option<Vec<Statement>>,
+        in_else: bool,
+    },
+    While {
+        cond: Vec<String>,
+        body: Vec<Statement>,
+    },
+}
+
+impl Builder {
+    /// Append a finished statement to whichever body is currently open.
+    fn push(&mut self, statement: Statement) {
+        match *self {
+            Builder::Func(_, ref mut body) => body.push(statement),
+            Builder::While { ref mut body, .. } => body.push(statement),
+            Builder::If { ref mut then_body, ref mut else_body, in_else, .. } => {
+                if in_else {
+                    else_body.get_or_insert_with(Vec::new).push(statement);
+                } else {
+                    then_body.push(statement);
+                }
+            },
+        }
+    }
+}
+
+/// The parser state is a stack of open blocks. A statement is appended to the
+/// innermost block; closing a block folds it into the next one out (or into the
+/// `Program` when the outermost function closes).
+#[derive(Clone, Debug)]
+struct ParseState {
+    stack: Vec<Builder>,
+    imports: Vec<String>,
+    top_level: Vec<Statement>,
+    allow_top_level: bool,
 }
 
 impl ParseState {
-    pub fn transform(self, pattern: LexicalPattern, program: &mut Program) -> errors::Result<ParseState> {
-        let new_state = match pattern {
+    fn new(allow_top_level: bool) -> ParseState {
+        ParseState { stack: vec![], imports: vec![], top_level: vec![], allow_top_level: allow_top_level }
+    }
+
+    fn push_statement(&mut self, statement: Statement, program: &mut Program) -> errors::Result<()> {
+        match self.stack.last_mut() {
+            Some(builder) => {
+                builder.push(statement);
+                Ok(())
+            },
+            None => {
+                let _ = program;
+                bail!(errors::ErrorKind::InvalidProgram("expect start mark when outside, but got statement".to_owned()))
+            },
+        }
+    }
+
+    pub fn transform_in_place(&mut self, pattern: LexicalPattern, program: &mut Program) -> errors::Result<()> {
+        match pattern {
             LexicalPattern::FuncStart(name) => {
-                match self {
-                    ParseState::ConstructFunc(_, _) => bail!(errors::ErrorKind::InvalidProgram("already started to construct function, but got function start again".to_owned())),
-                    ParseState::Outside => ParseState::ConstructFunc(name, Function(vec![])),
+                if !self.stack.is_empty() {
+                    bail!(errors::ErrorKind::InvalidProgram("cannot define a function inside a block".to_owned()));
                 }
+                self.stack.push(Builder::Func(name, vec![]));
             },
-            LexicalPattern::FuncEnd => {
-                match self {
-                    ParseState::ConstructFunc(n, f) => {
-                        program.insert(n, f);
-                        ParseState::Outside
+            LexicalPattern::IfStart(cond) => {
+                if self.stack.is_empty() {
+                    bail!(errors::ErrorKind::InvalidProgram("expect start mark when outside, but got if".to_owned()));
+                }
+                self.stack.push(Builder::If { cond: cond, then_body: vec![], else_body: None, in_else: false });
+            },
+            LexicalPattern::WhileStart(cond) => {
+                if self.stack.is_empty() {
+                    bail!(errors::ErrorKind::InvalidProgram("expect start mark when outside, but got while".to_owned()));
+                }
+                self.stack.push(Builder::While { cond: cond, body: vec![] });
+            },
+            LexicalPattern::ElseStart => {
+                match self.stack.last_mut() {
+                    Some(&mut Builder::If { ref mut in_else, ref mut else_body, .. }) if !*in_else => {
+                        *in_else = true;
+                        *else_body = Some(vec![]);
                     },
-                    ParseState::Outside => bail!(errors::ErrorKind::InvalidProgram("expect start mark when outside, but got function end".to_owned())),
+                    _ => bail!(errors::ErrorKind::InvalidProgram("`else` without a matching `if`".to_owned())),
                 }
             },
-            LexicalPattern::Statement(statement) => {
-                match self {
-                    ParseState::ConstructFunc(n, mut f) => {
-                        f.0.push(statement);
-                        ParseState::ConstructFunc(n, f)
+            LexicalPattern::FuncEnd => {
+                match self.stack.pop() {
+                    Some(Builder::Func(name, body)) => {
+                        program.insert(name, Function(body));
                     },
-                    ParseState::Outside => bail!(errors::ErrorKind::InvalidProgram("expect start mark when outside, but got statement".to_owned())),
+                    Some(Builder::If { cond, then_body, else_body, .. }) => {
+                        self.push_statement(Statement::If { cond: cond, then_body: then_body, else_body: else_body }, program)?;
+                    },
+                    Some(Builder::While { cond, body }) => {
+                        self.push_statement(Statement::While { cond: cond, body: body }, program)?;
+                    },
+                    None => bail!(errors::ErrorKind::InvalidProgram("expect start mark when outside, but got function end".to_owned())),
                 }
             },
-            LexicalPattern::Empty => {
-                self
+            LexicalPattern::Statement(statement) => {
+                // `source <path>` is a top-level directive that pulls in
+                // another file's functions rather than a runtime statement.
+                if self.stack.is_empty() {
+                    if let Statement::Execution(ref args) = statement {
+                        if args.first().map(String::as_str) == Some("source") {
+                            match args.get(1) {
+                                Some(path) => {
+                                    self.imports.push(path.clone());
+                                    return Ok(());
+                                },
+                                None => bail!(errors::ErrorKind::InvalidProgram("`source` requires a path".to_owned())),
+                            }
+                        }
+                    }
+                    // Outside any block, a statement is either an error (file
+                    // mode) or runs immediately against the live REPL session.
+                    if self.allow_top_level {
+                        self.top_level.push(statement);
+                        return Ok(());
+                    }
+                }
+                self.push_statement(statement, program)?;
             },
-        };
-
-        Ok(new_state)
-    }
-
-    pub fn transform_in_place(&mut self, pattern: LexicalPattern, program: &mut Program) -> errors::Result<()> {
-        *self = mem::replace(self, ParseState::Outside).transform(pattern, program)?;
+            LexicalPattern::Empty => {},
+        }
 
         Ok(())
     }
 
     pub fn end_success(self) -> errors::Result<()> {
-        match self {
-            ParseState::ConstructFunc(..) => bail!(errors::ErrorKind::InvalidProgram("haven't end".to_owned())),
-            ParseState::Outside => Ok(()),
+        if self.stack.is_empty() {
+            Ok(())
+        } else {
+            bail!(errors::ErrorKind::InvalidProgram("haven't end".to_owned()))
         }
     }
 }
 
-struct Environment {
+/// Upper bound on nested `Function` calls before we treat the program as
+/// infinitely recursive and bail instead of letting the native stack blow up.
+const RECURSION_LIMIT: usize = 256;
+
+struct Environment<'a> {
     table: BTreeMap<String, String>,
+    program: &'a Program,
+    depth: usize,
 }
 
-impl Environment {
-    pub fn new() -> Environment {
-        let table = BTreeMap::new();
+impl<'a> Environment<'a> {
+    pub fn new(program: &'a Program) -> Environment<'a> {
+        Environment::with_table(program, BTreeMap::new())
+    }
 
-        Environment { table: table }
+    pub fn with_table(program: &'a Program, table: BTreeMap<String, String>) -> Environment<'a> {
+        Environment { table: table, program: program, depth: 0 }
     }
 
-    pub fn exec_assignment(&mut self, assignment: &Assignment) {
+    pub fn exec_assignment(&mut self, assignment: &Assignment) -> errors::Result<()> {
+        let value = self.expand(&assignment.value)?;
         if let Some(variable) = self.table.get_mut(&assignment.variable) {
-            *variable = assignment.value.clone();
-            return
+            *variable = value;
+            return Ok(());
         }
-        self.table.insert(assignment.variable.clone(), assignment.value.clone());
+        self.table.insert(assignment.variable.clone(), value);
+        Ok(())
     }
 
-    pub fn exec_execution(&self, args: &[String]) {
-        let cmdline: Vec<String> = args.into_iter().map(|x| self.expand(x).to_owned()).collect();
+    pub fn exec_execution(&mut self, args: &[String]) -> errors::Result<()> {
+        let cmdline: Vec<String> = args.into_iter().map(|x| self.expand(x)).collect::<errors::Result<_>>()?;
+
+        if cmdline.is_empty() {
+            return Ok(());
+        }
+
+        let (exec, argv) = cmdline.split_at(1);
+
+        // A command whose name matches a function in the program is a
+        // subroutine call rather than an external process, which is what
+        // gives us recursion.
+        if let Some(function) = self.program.get(&exec[0]) {
+            return self.exec_function(function);
+        }
+
+        let status = ::std::process::Command::new(&exec[0])
+            .args(argv)
+            .status()
+            .map_err(|e| errors::ErrorKind::InvalidProgram(format!("cannot execute '{}': {}", &exec[0], e)))?;
+
+        // Record the exit code so `$?` reflects the last external command run
+        // as a statement, shell-style. Condition commands and function calls
+        // leave `$?` unchanged.
+        let code = status.code().unwrap_or(-1);
+        self.table.insert("?".to_owned(), code.to_string());
+
+        Ok(())
+    }
+
+    /// Run a condition command and report whether it succeeded (exit status 0),
+    /// shell-style. A condition that names a function runs that function and is
+    /// treated as true.
+    pub fn eval_condition(&mut self, cond: &[String]) -> errors::Result<bool> {
+        let cmdline: Vec<String> = cond.into_iter().map(|x| self.expand(x)).collect::<errors::Result<_>>()?;
+
+        if cmdline.is_empty() {
+            return Ok(true);
+        }
+
+        let (exec, argv) = cmdline.split_at(1);
 
-        if !cmdline.is_empty() {
-            let (exec, argv) = cmdline.split_at(1);
-            let _ = ::std::process::Command::new(&exec[0])
-                .args(argv)
-                .status()
-                .map_err(|e| println!("Command failed: {}", e));
+        if let Some(function) = self.program.get(&exec[0]) {
+            self.exec_function(function)?;
+            return Ok(true);
         }
+
+        let status = ::std::process::Command::new(&exec[0])
+            .args(argv)
+            .status()
+            .map_err(|e| errors::ErrorKind::InvalidProgram(format!("cannot execute '{}': {}", &exec[0], e)))?;
+
+        Ok(status.success())
     }
 
-    pub fn exec_statement(&mut self, statement: &Statement) {
+    pub fn exec_statement(&mut self, statement: &Statement) -> errors::Result<()> {
         match *statement {
             Statement::Assignment(ref assignment) => {
                 self.exec_assignment(assignment)
@@ -171,21 +515,189 @@ impl Environment {
             Statement::Execution(ref args) => {
                 self.exec_execution(args)
             },
+            Statement::If { ref cond, ref then_body, ref else_body } => {
+                if self.eval_condition(cond)? {
+                    self.exec_body(then_body)?;
+                } else if let Some(ref body) = *else_body {
+                    self.exec_body(body)?;
+                }
+                Ok(())
+            },
+            Statement::While { ref cond, ref body } => {
+                while self.eval_condition(cond)? {
+                    self.exec_body(body)?;
+                }
+                Ok(())
+            },
         }
     }
 
-    pub fn exec_function(&mut self, function: &Function) {
-        for statement in &function.0 {
-            self.exec_statement(statement)
+    fn exec_body(&mut self, body: &[Statement]) -> errors::Result<()> {
+        for statement in body {
+            self.exec_statement(statement)?;
         }
+        Ok(())
     }
 
-    fn expand<'a>(&'a self, arg: &'a str) -> &'a str {
-        if arg.starts_with('$') {
-            self.table.get(&arg[1..]).map(String::as_str).unwrap_or("")
-        } else {
-            arg
+    pub fn exec_function(&mut self, function: &Function) -> errors::Result<()> {
+        if self.depth >= RECURSION_LIMIT {
+            bail!(errors::ErrorKind::RecursionLimit(RECURSION_LIMIT));
         }
+
+        self.depth += 1;
+        let result = self.exec_body(&function.0);
+        self.depth -= 1;
+
+        result
+    }
+
+    /// Expand an argument, resolving `$name` / `$?` variable references and
+    /// `$(...)` command substitutions. Everything else is copied verbatim; the
+    /// scan is recursive so a substitution body may itself contain references.
+    fn expand(&self, arg: &str) -> errors::Result<String> {
+        let bytes = arg.as_bytes();
+        let mut out = String::with_capacity(arg.len());
+        let mut i = 0;
+
+        while i < bytes.len() {
+            if bytes[i] != b'$' {
+                // Advance one whole UTF-8 char.
+                let start = i;
+                i += 1;
+                while i < bytes.len() && (bytes[i] & 0xC0) == 0x80 {
+                    i += 1;
+                }
+                out.push_str(&arg[start..i]);
+                continue;
+            }
+
+            // `$(...)` — command substitution, matched to its closing paren.
+            if arg[i + 1..].starts_with('(') {
+                let rest = &arg[i + 2..];
+                let mut depth = 1;
+                let mut end = None;
+                for (off, c) in rest.char_indices() {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                end = Some(off);
+                                break;
+                            }
+                        },
+                        _ => {},
+                    }
+                }
+                match end {
+                    Some(off) => {
+                        let inner = self.expand(&rest[..off])?;
+                        out.push_str(&self.capture(&inner)?);
+                        i += 2 + off + 1;
+                    },
+                    None => bail!(errors::ErrorKind::InvalidProgram("unterminated command substitution".to_owned())),
+                }
+                continue;
+            }
+
+            // `$name` / `$?` — variable reference.
+            let name_start = i + 1;
+            let mut j = name_start;
+            if arg[name_start..].starts_with('?') {
+                j += 1;
+            } else {
+                for c in arg[name_start..].chars() {
+                    if c.is_alphanumeric() || c == '_' {
+                        j += c.len_utf8();
+                    } else {
+                        break;
+                    }
+                }
+            }
+
+            if j == name_start {
+                out.push('$');
+            } else {
+                out.push_str(self.table.get(&arg[name_start..j]).map(String::as_str).unwrap_or(""));
+            }
+            i = j;
+        }
+
+        Ok(out)
+    }
+
+    /// Run a captured command and return its standard output with all trailing
+    /// newlines stripped, matching shell command substitution.
+    fn capture(&self, command: &str) -> errors::Result<String> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        if args.is_empty() {
+            return Ok(String::new());
+        }
+
+        let (exec, argv) = args.split_at(1);
+        let output = ::std::process::Command::new(exec[0])
+            .args(argv)
+            .output()
+            .map_err(|e| errors::ErrorKind::InvalidProgram(format!("cannot execute '{}': {}", exec[0], e)))?;
+
+        let mut stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        while stdout.ends_with('\n') {
+            stdout.pop();
+        }
+        Ok(stdout)
+    }
+}
+
+/// Owns every source string loaded from disk and merges their functions into a
+/// single shared `Program`. Canonical paths are tracked so a file is parsed at
+/// most once and an include cycle is reported rather than followed forever.
+struct Loader {
+    sources: Vec<String>,
+    loaded: BTreeSet<PathBuf>,
+    loading: Vec<PathBuf>,
+}
+
+impl Loader {
+    fn new() -> Loader {
+        Loader { sources: vec![], loaded: BTreeSet::new(), loading: vec![] }
+    }
+
+    /// Parse `path` and merge its functions into `program`, recursively loading
+    /// anything it `source`s. Relative imports resolve against the including
+    /// file's directory.
+    fn load(&mut self, path: &Path, program: &mut Program) -> errors::Result<()> {
+        let canonical = path.canonicalize()?;
+
+        if self.loading.iter().any(|p| *p == canonical) {
+            bail!(errors::ErrorKind::ImportCycle(canonical.display().to_string()));
+        }
+        if self.loaded.contains(&canonical) {
+            return Ok(());
+        }
+
+        let mut source = String::new();
+        File::open(&canonical)?.read_to_string(&mut source)?;
+        self.sources.push(source);
+
+        let (functions, imports) = parse_to_ast(self.sources.last().unwrap())?;
+
+        self.loading.push(canonical.clone());
+
+        let base = canonical.parent().unwrap_or_else(|| Path::new("."));
+        for import in imports {
+            let mut child = PathBuf::from(base);
+            child.push(&import);
+            self.load(&child, program)?;
+        }
+
+        for (name, function) in functions {
+            program.insert(name, function);
+        }
+
+        self.loading.pop();
+        self.loaded.insert(canonical);
+
+        Ok(())
     }
 }
 
@@ -193,43 +705,219 @@ pub fn parse_file_to_ast(filename: &str) -> errors::Result<Program> {
     let mut cwd = env::current_dir()?;
     cwd.push(filename);
 
-    let f = File::open(cwd)?;
-    let f = BufReader::new(f);
+    let mut program = Program::new();
+    Loader::new().load(&cwd, &mut program)?;
+
+    Ok(program)
+}
+
+/// Retains the original source so errors can be reported against it, and maps
+/// byte offsets back to line/column positions.
+struct SourceMap<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    fn new(source: &'a str) -> SourceMap<'a> {
+        let mut line_starts = vec![0];
+        for (i, b) in source.bytes().enumerate() {
+            if b == b'\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        SourceMap { source: source, line_starts: line_starts }
+    }
+
+    /// Map a byte offset to a zero-based `(line, column)` pair.
+    fn locate(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        (line, offset - self.line_starts[line])
+    }
+
+    fn line_text(&self, line: usize) -> &str {
+        let start = self.line_starts[line];
+        let end = self.line_starts.get(line + 1).map_or(self.source.len(), |&e| e);
+        self.source[start..end].trim_end_matches('\n')
+    }
+
+    /// Build a `Parse` error carrying a caret-underlined rendering of the span
+    /// `[offset, offset + len)` together with its line/column.
+    fn error(&self, offset: usize, len: usize, message: &str) -> errors::Error {
+        let (line, col) = self.locate(offset);
+        let rendered = render_diagnostic(self, line, col, len, message);
+        errors::ErrorKind::Parse(rendered, line + 1, col + 1).into()
+    }
+}
+
+/// Render a single-line diagnostic in the style of `codespan-reporting`: the
+/// offending source line above a `^^^` underline annotated with the message.
+fn render_diagnostic(map: &SourceMap, line: usize, col: usize, len: usize, message: &str) -> String {
+    let num = (line + 1).to_string();
+    let gutter = " ".repeat(num.len());
+    let underline = format!("{}{}", " ".repeat(col), "^".repeat(len.max(1)));
+
+    format!(
+        "error: {message}\n{gutter} |\n{num} | {text}\n{gutter} | {underline}",
+        message = message,
+        gutter = gutter,
+        num = num,
+        text = map.line_text(line),
+        underline = underline,
+    )
+}
+
+/// Byte span covered by a logical line's tokens, used to point the diagnostic
+/// underline at the whole offending line.
+fn line_span(line: &[Spanned]) -> Option<(usize, usize)> {
+    match (line.first(), line.last()) {
+        (Some(&(_, ref first)), Some(&(_, ref last))) => Some((first.start, last.end - first.start)),
+        _ => None,
+    }
+}
 
-    parse_to_ast(f.lines())
+fn parse_to_ast(source: &str) -> errors::Result<(Program, Vec<String>)> {
+    let (program, imports, _) = parse_source(source, false)?;
+    Ok((program, imports))
 }
 
-fn parse_to_ast<T, I>(content: T) -> errors::Result<Program>
-    where T: IntoIterator<Item=::std::io::Result<I>>,
-          I: AsRef<str>
-{
+/// Parse a source string into functions, `source` imports, and — when
+/// `allow_top_level` is set (REPL mode) — any statements typed outside a block.
+fn parse_source(source: &str, allow_top_level: bool) -> errors::Result<(Program, Vec<String>, Vec<Statement>)> {
     let mut program = BTreeMap::new();
+    let map = SourceMap::new(source);
 
-    let mut parser = ParseState::Outside;
-    for l in content {
-        if let Some(p) = LexicalPattern::from_line(l?.as_ref()) {
-            parser.transform_in_place(p, &mut program)?;
-        } else {
-            bail!(errors::ErrorKind::InvalidProgram("encounter bad line".to_owned()));
+    let tokens: Vec<Spanned> = Token::lexer(source).spanned().collect();
+
+    let mut parser = ParseState::new(allow_top_level);
+    // `logos` flattens the whole source into one token stream; split it back
+    // into logical lines on `Newline` and classify each line on its own.
+    for line in tokens.split(|&(ref t, _)| *t == Token::Newline) {
+        let (offset, len) = line_span(line).unwrap_or((source.len(), 1));
+        match LexicalPattern::from_tokens(line) {
+            Some(p) => {
+                if let Err(e) = parser.transform_in_place(p, &mut program) {
+                    return Err(map.error(offset, len, &format!("{}", e)));
+                }
+            },
+            None => return Err(map.error(offset, len, "cannot parse this line")),
         }
     }
-    parser.end_success()?;
+    let imports = mem::replace(&mut parser.imports, vec![]);
+    let top_level = mem::replace(&mut parser.top_level, vec![]);
+    if let Err(e) = parser.end_success() {
+        return Err(map.error(source.len(), 1, &format!("{}", e)));
+    }
 
-    Ok(program)
+    Ok((program, imports, top_level))
 }
 
-pub fn run(filename: &str) -> errors::Result<()> {
-    let mut env = Environment::new();
+/// Net number of unclosed blocks in `source`, used by the REPL to tell a
+/// complete entry from one still waiting for its closing `}`.
+fn block_depth(source: &str) -> i32 {
+    let mut depth = 0;
+    for (token, _) in Token::lexer(source).spanned() {
+        match token {
+            Token::LBraceFuncStart => depth += 1,
+            Token::RBrace => depth -= 1,
+            _ => {},
+        }
+    }
+    depth
+}
 
+pub fn run(filename: &str) -> errors::Result<()> {
     let program = parse_file_to_ast(filename)?;
+    let mut env = Environment::new(&program);
+
     if let Some(main_func) = program.get("main") {
-        env.exec_function(main_func);
+        env.exec_function(main_func)?;
         return Ok(());
     } else {
         bail!(errors::ErrorKind::InvalidProgram("no main".to_owned()));
     }
 }
 
+/// Primary prompt shown when the REPL is ready for a fresh entry.
+const PROMPT: &str = "mysh> ";
+/// Continuation prompt shown while a block is still open.
+const PROMPT_CONT: &str = "  ... ";
+
+/// Run an interactive read-eval-print loop. Functions and variables accumulate
+/// across entries, an entry that opens a block keeps reading until it closes,
+/// and parse/evaluation errors are printed without tearing down the session.
+pub fn repl() -> errors::Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    let mut program = Program::new();
+    let mut table: BTreeMap<String, String> = BTreeMap::new();
+    let mut buffer = String::new();
+
+    prompt(PROMPT)?;
+    while let Some(line) = lines.next() {
+        buffer.push_str(&line?);
+        buffer.push('\n');
+
+        // Keep buffering until every block opened in this entry is closed.
+        if block_depth(&buffer) > 0 {
+            prompt(PROMPT_CONT)?;
+            continue;
+        }
+
+        if !buffer.trim().is_empty() {
+            if let Err(e) = eval_repl(&buffer, &mut program, &mut table) {
+                eprintln!("{}", e);
+            }
+        }
+        buffer.clear();
+        prompt(PROMPT)?;
+    }
+
+    // A trailing EOF mid-block still gets a chance to run.
+    if !buffer.trim().is_empty() {
+        if let Err(e) = eval_repl(&buffer, &mut program, &mut table) {
+            eprintln!("{}", e);
+        }
+    }
+    println!();
+
+    Ok(())
+}
+
+fn prompt(text: &str) -> errors::Result<()> {
+    print!("{}", text);
+    io::stdout().flush()?;
+    Ok(())
+}
+
+/// Parse one complete REPL entry: merge any function definitions into the live
+/// `Program` and execute any top-level statements against the live variables.
+fn eval_repl(source: &str, program: &mut Program, table: &mut BTreeMap<String, String>) -> errors::Result<()> {
+    let (functions, imports, top_level) = parse_source(source, true)?;
+
+    let mut loader = Loader::new();
+    let base = env::current_dir()?;
+    for import in imports {
+        let mut child = base.clone();
+        child.push(&import);
+        loader.load(&child, program)?;
+    }
+
+    for (name, function) in functions {
+        program.insert(name, function);
+    }
+
+    let mut env = Environment::with_table(program, mem::replace(table, BTreeMap::new()));
+    let result = env.exec_body(&top_level);
+    *table = mem::replace(&mut env.table, BTreeMap::new());
+
+    result
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -253,4 +941,145 @@ mod test {
             assert_eq!(t.1, name_valid(t.0), "input: {}", t.1);
         }
     }
+
+    fn main_body(source: &str) -> Vec<Statement> {
+        let (program, _) = parse_to_ast(source).unwrap();
+        program.get("main").unwrap().0.clone()
+    }
+
+    #[test]
+    fn test_quoted_argument_kept_intact() {
+        let body = main_body("main(){\necho \"hello world\"\n}\n");
+        match body[0] {
+            Statement::Execution(ref args) => assert_eq!(args, &["echo", "hello world"]),
+            ref other => panic!("expected execution, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_escapes_in_string_literal() {
+        let body = main_body("main(){\necho \"a\\tb\\\"c\"\n}\n");
+        match body[0] {
+            Statement::Execution(ref args) => assert_eq!(args, &["echo", "a\tb\"c"]),
+            ref other => panic!("expected execution, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_comment_discarded_but_quoted_hash_kept() {
+        let body = main_body("main(){\nx=\"a=b # c\" # trailing\n}\n");
+        match body[0] {
+            Statement::Assignment(ref a) => {
+                assert_eq!(a.variable, "x");
+                assert_eq!(a.value, "a=b # c");
+            },
+            ref other => panic!("expected assignment, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_if_else_chain() {
+        let body = main_body("main(){\nif test -f a (){\necho yes\nelse {\necho no\n}\n}\n");
+        match body[0] {
+            Statement::If { ref cond, ref then_body, ref else_body } => {
+                assert_eq!(cond, &["test", "-f", "a"]);
+                assert_eq!(then_body.len(), 1);
+                assert_eq!(else_body.as_ref().map(|b| b.len()), Some(1));
+            },
+            ref other => panic!("expected if, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        let body = main_body("main(){\nwhile outer (){\nwhile inner (){\necho hi\n}\n}\n}\n");
+        match body[0] {
+            Statement::While { ref cond, ref body } => {
+                assert_eq!(cond, &["outer"]);
+                match body[0] {
+                    Statement::While { ref cond, .. } => assert_eq!(cond, &["inner"]),
+                    ref other => panic!("expected inner while, got {:?}", other),
+                }
+            },
+            ref other => panic!("expected while, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_exit_code_into_question_mark() {
+        let program = Program::new();
+        let mut env = Environment::new(&program);
+
+        env.exec_execution(&["true".to_owned()]).unwrap();
+        assert_eq!(env.expand("$?").unwrap(), "0");
+
+        env.exec_execution(&["false".to_owned()]).unwrap();
+        assert_eq!(env.expand("$?").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_command_substitution_interpolated() {
+        let program = Program::new();
+        let env = Environment::new(&program);
+
+        assert_eq!(env.expand("$(echo hello)").unwrap(), "hello");
+        assert_eq!(env.expand("[$(echo hi)]").unwrap(), "[hi]");
+    }
+
+    #[test]
+    fn test_command_substitution_through_parser() {
+        // Exercise the full lexer/parser/expand path, not a hand-built string.
+        let (program, _) = parse_to_ast("main(){\nx=$(echo hello)\n}\n").unwrap();
+        let mut env = Environment::new(&program);
+        env.exec_function(program.get("main").unwrap()).unwrap();
+        assert_eq!(env.table.get("x").map(String::as_str), Some("hello"));
+    }
+
+    #[test]
+    fn test_bare_parens_preserved_in_argument() {
+        let body = main_body("main(){\necho (foo)\n}\n");
+        match body[0] {
+            Statement::Execution(ref args) => assert_eq!(args, &["echo", "(foo)"]),
+            ref other => panic!("expected execution, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_source_directive_collected_as_import() {
+        let (program, imports) = parse_to_ast("source lib.mysh\nmain(){\necho hi\n}\n").unwrap();
+        assert_eq!(imports, &["lib.mysh"]);
+        assert!(program.contains_key("main"));
+    }
+
+    #[test]
+    fn test_parse_error_carries_position_and_caret() {
+        // A statement outside any function is an error on its line.
+        let err = parse_to_ast("main(){\n}\noops\n").unwrap_err();
+        match *err.kind() {
+            errors::ErrorKind::Parse(ref rendered, line, col) => {
+                assert_eq!((line, col), (3, 1));
+                assert!(rendered.contains("oops"), "rendered: {}", rendered);
+                assert!(rendered.contains('^'), "rendered: {}", rendered);
+            },
+            ref other => panic!("expected parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_block_depth_tracks_open_blocks() {
+        assert_eq!(block_depth("echo hi\n"), 0);
+        assert_eq!(block_depth("main(){\n"), 1);
+        assert_eq!(block_depth("main(){\nif a (){\n"), 2);
+        assert_eq!(block_depth("main(){\nif a (){\n}\n}\n"), 0);
+    }
+
+    #[test]
+    fn test_repl_collects_top_level_statements() {
+        let (program, _, top_level) = parse_source("x=1\necho hi\n", true).unwrap();
+        assert!(program.is_empty());
+        assert_eq!(top_level.len(), 2);
+
+        // Without the REPL flag the same statements are a parse error.
+        assert!(parse_source("echo hi\n", false).is_err());
+    }
 }
@@ -5,5 +5,9 @@ mod interpreter;
 mod errors;
 
 fn main() {
-    interpreter::run(&std::env::args().nth(1).expect("must supply script filename!")).unwrap();
+    let result = match std::env::args().nth(1) {
+        Some(filename) => interpreter::run(&filename),
+        None => interpreter::repl(),
+    };
+    result.unwrap();
 }